@@ -0,0 +1,127 @@
+//! The proc-macro half of `ffi_helpers`.
+//!
+//! This crate exists purely so `ffi_helpers` can expose a `#[catch_panic]`
+//! attribute; see the docs on [`catch_panic`] for what it actually does.
+//! Following [relay_ffi's `catch_unwind`][relay-ffi], it rewrites an
+//! `extern "C"` function so the whole body runs inside `catch_unwind`,
+//! turning a returned `Err` into a call to `ffi_helpers::update_last_error`
+//! and a panic into a call to `ffi_helpers::update_last_panic_error` instead
+//! of unwinding across the FFI boundary.
+//!
+//! [relay-ffi]: https://github.com/getsentry/relay
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, AttributeArgs, Block, ItemFn, Lit, Meta, NestedMeta};
+
+/// Wrap an `extern "C"` function's body in a `catch_unwind`, turning a
+/// returned `Err` into a call to `update_last_error()` and a panic into a
+/// call to `update_last_panic_error()`, and returning a sentinel value
+/// instead of unwinding.
+///
+/// The function you write is allowed to return a `Result<T, E>` and use
+/// `?` freely; the *emitted* function keeps the signature's declared
+/// return type (which must itself be the sentinel's type, e.g. `c_int`),
+/// so from C's point of view it's still an infallible call.
+///
+/// By default the sentinel is `Default::default()`. Use
+/// `#[catch_panic(sentinel = -1)]` to return something else on failure.
+///
+/// Your `Err` type only needs to implement `std::error::Error`;
+/// `ffi_helpers::HasErrorCode` is blanket-implemented for every such type,
+/// so a returned `Err` always reports `ErrorCode::UNKNOWN` through
+/// `last_error_code()`; a caught panic reports `ErrorCode::PANIC`.
+///
+/// # Examples
+///
+/// ```
+/// use ffi_helpers_derive::catch_panic;
+/// use std::fmt;
+/// use std::os::raw::c_int;
+///
+/// #[derive(Debug)]
+/// struct Overflow;
+///
+/// impl fmt::Display for Overflow {
+///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+///         write!(f, "overflow")
+///     }
+/// }
+///
+/// impl std::error::Error for Overflow {}
+///
+/// #[no_mangle]
+/// #[catch_panic(sentinel = -1)]
+/// pub extern "C" fn add_one(x: c_int) -> c_int {
+///     x.checked_add(1).ok_or(Overflow)
+/// }
+///
+/// assert_eq!(add_one(1), 2);
+/// assert_eq!(add_one(c_int::max_value()), -1);
+/// ```
+#[proc_macro_attribute]
+pub fn catch_panic(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let func = parse_macro_input!(input as ItemFn);
+
+    let sentinel = match sentinel_expr(&args) {
+        Ok(sentinel) => sentinel,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+        ..
+    } = func;
+
+    let body = rewrite_body(&block);
+
+    let expanded = quote! {
+        #(#attrs)*
+        #vis #sig {
+            match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| #body)) {
+                Ok(Ok(value)) => value,
+                Ok(Err(error)) => {
+                    ::ffi_helpers::update_last_error(error);
+                    #sentinel
+                }
+                Err(panic) => {
+                    ::ffi_helpers::update_last_panic_error(::ffi_helpers::panic_to_error(panic));
+                    #sentinel
+                }
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+/// Pull the user's function body out into its own closure so `?` inside it
+/// propagates into a `Result` we can match on, instead of trying to `?` out
+/// of the generated `extern "C"` function (which doesn't return `Result`).
+fn rewrite_body(block: &Block) -> proc_macro2::TokenStream {
+    quote! {
+        (|| #block)()
+    }
+}
+
+fn sentinel_expr(args: &[NestedMeta]) -> syn::Result<proc_macro2::TokenStream> {
+    for arg in args {
+        if let NestedMeta::Meta(Meta::NameValue(name_value)) = arg {
+            if name_value.path.is_ident("sentinel") {
+                return Ok(match &name_value.lit {
+                    Lit::Int(i) => quote! { #i },
+                    Lit::Float(f) => quote! { #f },
+                    other => quote! { #other },
+                });
+            }
+        }
+    }
+
+    Ok(quote! { Default::default() })
+}
@@ -0,0 +1,102 @@
+//! Capturing a backtrace for the last panic.
+//!
+//! `error_message()` only ever gives you a one-line [`Display`] of the last
+//! error, which isn't much help when that error came from a panic deep
+//! inside some dependency. To get anything better we need to grab a
+//! backtrace *while the panic is unwinding*, because by the time
+//! `catch_unwind()` hands back the payload all that location information is
+//! gone.
+//!
+//! We do that with a [`std::panic::set_hook()`] handler which records a
+//! [`Backtrace`] plus the panic's file/line/column into a thread-local,
+//! mirroring how [Sentry's `relay_ffi`][relay-ffi] attaches a backtrace to
+//! every error it reports. [`crate::panic_to_error()`] then picks this up
+//! and attaches it to the [`PanicError`](crate::PanicError) it produces.
+//!
+//! Resolving a backtrace's symbols is not free, so capturing is off by
+//! default; call [`install_panic_hook()`] and [`enable_backtraces()`] once at
+//! start-up if you want them.
+//!
+//! [relay-ffi]: https://github.com/getsentry/relay
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fmt::{self, Display};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether panics should capture a backtrace at all. Off by default because
+/// resolving symbols is comparatively expensive.
+static BACKTRACES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn backtrace capturing on or off.
+///
+/// This is a single global flag (not per-thread) so it's usually set once,
+/// near the start of your program.
+pub fn enable_backtraces(enable: bool) {
+    BACKTRACES_ENABLED.store(enable, Ordering::SeqCst);
+}
+
+/// A backtrace captured at the point a panic occurred, together with the
+/// location the `panic!()` call itself happened at.
+#[derive(Debug)]
+pub struct CapturedBacktrace {
+    backtrace: Backtrace,
+    location: Option<String>,
+}
+
+impl Display for CapturedBacktrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = &self.location {
+            writeln!(f, "panicked at {}", location)?;
+        }
+
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+thread_local!(
+    static LAST_BACKTRACE: RefCell<Option<CapturedBacktrace>> = RefCell::new(None);
+);
+
+/// Install the panic hook which captures a [`CapturedBacktrace`] for every
+/// panic, on whichever thread it happens, into that thread's store (as long
+/// as [`enable_backtraces()`] has been called). The hook is process-wide and
+/// wraps the previously installed hook, which still runs after the capture.
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        if BACKTRACES_ENABLED.load(Ordering::SeqCst) {
+            let backtrace = Backtrace::force_capture();
+            let location = info.location().map(|l| l.to_string());
+
+            LAST_BACKTRACE.with(|last| {
+                *last.borrow_mut() = Some(CapturedBacktrace { backtrace, location });
+            });
+        }
+
+        previous(info);
+    }));
+}
+
+/// Take the backtrace captured by the most recent panic on this thread, if
+/// any, clearing it in the process.
+pub fn take_last_backtrace() -> Option<CapturedBacktrace> {
+    LAST_BACKTRACE.with(|last| last.borrow_mut().take())
+}
+
+/// Put a previously-[`take_last_backtrace()`]-n `CapturedBacktrace` back,
+/// for callers that need to retry after failing to consume it (mirroring how
+/// [`crate::error_message()`] restores the last error on a too-small
+/// buffer).
+pub(crate) fn restore_last_backtrace(captured: CapturedBacktrace) {
+    LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(captured));
+}
+
+/// Look at the backtrace captured by the most recent panic on this thread,
+/// if any, without draining it, so [`take_last_backtrace()`] still sees it
+/// afterwards.
+pub(crate) fn peek_last_backtrace() -> Option<String> {
+    LAST_BACKTRACE.with(|last| last.borrow().as_ref().map(|b| format!("{}", b)))
+}
@@ -0,0 +1,550 @@
+//! A way to safely hand out owned Rust values to C callers without giving
+//! them a raw pointer.
+//!
+//! ## Theory
+//!
+//! Instead of `Box::into_raw()`-ing a value and trusting the C caller to
+//! hand back the exact same pointer later (and never touch it after it's
+//! been freed), we keep every value in a table on the Rust side and give the
+//! caller an opaque `u64` *handle* instead.
+//!
+//! Each handle is a packed `u64` containing:
+//!
+//! - the index of the slot the value lives in,
+//! - a generation counter for that slot, bumped every time the slot is
+//!   reused, so a handle to a freed value can be detected instead of
+//!   silently aliasing whatever happens to occupy the slot now,
+//! - a 16-bit identifier for the particular map the handle belongs to, so a
+//!   handle from one `HandleMap` can't accidentally be used with another,
+//! - a 3-bit type tag, and
+//! - a parity bit so a handle which has been mangled by the C side (e.g.
+//!   truncated to 32 bits) is likely to be caught before it's used.
+//!
+//! Looking a handle up therefore means decoding it, checking the map id and
+//! generation match what's in the slot, and only then touching the value.
+//! Any mismatch is returned as a [`HandleError`] instead of touching memory
+//! that may no longer be valid. The lookup methods don't call
+//! [`update_last_error()`] themselves; [`get_or_update_last_error()`] passes
+//! a failed [`HandleMap`] lookup on to it.
+
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, RwLock};
+
+use super::update_last_error;
+
+/// The number of bits used to store the slot index.
+const INDEX_BITS: u32 = 32;
+/// The number of bits used to store the generation counter.
+const GENERATION_BITS: u32 = 12;
+/// The number of bits used to store the map identifier.
+const MAP_ID_BITS: u32 = 16;
+/// The number of bits used to store the type tag.
+const TYPE_TAG_BITS: u32 = 3;
+
+const INDEX_SHIFT: u32 = 0;
+const GENERATION_SHIFT: u32 = INDEX_SHIFT + INDEX_BITS;
+const MAP_ID_SHIFT: u32 = GENERATION_SHIFT + GENERATION_BITS;
+const TYPE_TAG_SHIFT: u32 = MAP_ID_SHIFT + MAP_ID_BITS;
+const PARITY_SHIFT: u32 = TYPE_TAG_SHIFT + TYPE_TAG_BITS;
+
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: u64 = (1 << GENERATION_BITS) - 1;
+const MAP_ID_MASK: u64 = (1 << MAP_ID_BITS) - 1;
+const TYPE_TAG_MASK: u64 = (1 << TYPE_TAG_BITS) - 1;
+
+/// The generation counter only has `GENERATION_BITS` of room in an encoded
+/// handle, so the stored counter must wrap at the same width - otherwise a
+/// slot that's been reused more than `GENERATION_BITS` bits' worth of times
+/// ends up with a stored generation that can never match any handle we're
+/// able to encode again, permanently rejecting otherwise-valid handles as
+/// [`HandleError::UseAfterFree`].
+const MAX_GENERATION: u16 = GENERATION_MASK as u16;
+
+fn next_generation(generation: u16) -> u16 {
+    generation.wrapping_add(1) & MAX_GENERATION
+}
+
+/// A handle that has been decoded back into its constituent parts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct DecodedHandle {
+    index: u32,
+    generation: u16,
+    map_id: u16,
+    type_tag: u8,
+}
+
+fn encode(index: u32, generation: u16, map_id: u16, type_tag: u8) -> u64 {
+    let mut bits = (index as u64 & INDEX_MASK) << INDEX_SHIFT
+        | (generation as u64 & GENERATION_MASK) << GENERATION_SHIFT
+        | (map_id as u64 & MAP_ID_MASK) << MAP_ID_SHIFT
+        | (type_tag as u64 & TYPE_TAG_MASK) << TYPE_TAG_SHIFT;
+
+    if bits.count_ones() % 2 != 0 {
+        bits |= 1 << PARITY_SHIFT;
+    }
+
+    bits
+}
+
+fn decode(handle: u64) -> Result<DecodedHandle, HandleError> {
+    let without_parity = handle & !(1 << PARITY_SHIFT);
+    let expects_parity_bit = without_parity.count_ones() % 2 != 0;
+    let has_parity_bit = handle & (1 << PARITY_SHIFT) != 0;
+
+    if expects_parity_bit != has_parity_bit {
+        return Err(HandleError::CorruptHandle);
+    }
+
+    Ok(DecodedHandle {
+        index: ((handle >> INDEX_SHIFT) & INDEX_MASK) as u32,
+        generation: ((handle >> GENERATION_SHIFT) & GENERATION_MASK) as u16,
+        map_id: ((handle >> MAP_ID_SHIFT) & MAP_ID_MASK) as u16,
+        type_tag: ((handle >> TYPE_TAG_SHIFT) & TYPE_TAG_MASK) as u8,
+    })
+}
+
+/// Generate a random 16-bit identifier for a new map.
+///
+/// This needs to be unpredictable (not just unique), so a handle from one
+/// map can't be guessed and replayed against another - `RandomState` draws
+/// its keys from the OS's random number generator, which is exactly the
+/// kind of entropy we want here without pulling in a dedicated `rand`
+/// dependency.
+fn next_map_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// The ways looking up a handle can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandleError {
+    /// The handle's parity bit doesn't match its other fields, so it's
+    /// almost certainly been mangled (e.g. truncated) on the way from C.
+    CorruptHandle,
+    /// The handle belongs to a different `HandleMap`.
+    WrongMap,
+    /// The slot's generation doesn't match the handle's, meaning the value
+    /// this handle used to point to has since been removed.
+    UseAfterFree,
+    /// The handle's index is out of bounds for this map.
+    IndexOutOfBounds,
+}
+
+impl Display for HandleError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            HandleError::CorruptHandle => write!(f, "the handle's parity bit is invalid"),
+            HandleError::WrongMap => write!(f, "the handle belongs to a different map"),
+            HandleError::UseAfterFree => {
+                write!(f, "the handle refers to a value which has already been removed")
+            }
+            HandleError::IndexOutOfBounds => write!(f, "the handle's index is out of bounds"),
+        }
+    }
+}
+
+impl Error for HandleError {}
+
+enum Slot<T> {
+    Occupied { generation: u16, value: T },
+    Free { generation: u16, next_free: Option<u32> },
+}
+
+/// A table of owned `T`s, indexed by an opaque `u64` handle instead of a
+/// pointer.
+///
+/// See the [module-level documentation](index.html) for the rationale
+/// behind this.
+pub struct HandleMap<T> {
+    map_id: u16,
+    type_tag: u8,
+    slots: Vec<Slot<T>>,
+    free_list_head: Option<u32>,
+}
+
+impl<T> HandleMap<T> {
+    /// Create a new, empty `HandleMap`.
+    pub fn new() -> HandleMap<T> {
+        HandleMap::with_type_tag(0)
+    }
+
+    /// Create a new, empty `HandleMap`, tagging every handle it issues with
+    /// `type_tag`. The tag is carried in each handle, but lookups don't
+    /// check it: a handle is validated by its map id and generation only.
+    pub fn with_type_tag(type_tag: u8) -> HandleMap<T> {
+        HandleMap {
+            map_id: next_map_id(),
+            type_tag,
+            slots: Vec::new(),
+            free_list_head: None,
+        }
+    }
+
+    /// Insert a value into the map, returning the handle it can later be
+    /// retrieved with.
+    pub fn insert(&mut self, value: T) -> u64 {
+        let index = match self.free_list_head.take() {
+            Some(index) => {
+                let generation = match &self.slots[index as usize] {
+                    Slot::Free { generation, next_free } => {
+                        self.free_list_head = *next_free;
+                        *generation
+                    }
+                    Slot::Occupied { .. } => unreachable!("free list pointed at an occupied slot"),
+                };
+
+                self.slots[index as usize] = Slot::Occupied { generation, value };
+                index
+            }
+            None => {
+                let index = self.slots.len() as u32;
+                self.slots.push(Slot::Occupied { generation: 0, value });
+                index
+            }
+        };
+
+        let generation = match &self.slots[index as usize] {
+            Slot::Occupied { generation, .. } => *generation,
+            Slot::Free { .. } => unreachable!(),
+        };
+
+        encode(index, generation, self.map_id, self.type_tag)
+    }
+
+    /// Remove the value behind a handle, returning it to the caller.
+    pub fn remove(&mut self, handle: u64) -> Result<T, HandleError> {
+        let slot_index = self.resolve(handle)?;
+
+        let generation = match &self.slots[slot_index as usize] {
+            Slot::Occupied { generation, .. } => next_generation(*generation),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        };
+
+        let old = std::mem::replace(
+            &mut self.slots[slot_index as usize],
+            Slot::Free {
+                generation,
+                next_free: self.free_list_head,
+            },
+        );
+        self.free_list_head = Some(slot_index);
+
+        match old {
+            Slot::Occupied { value, .. } => Ok(value),
+            Slot::Free { .. } => unreachable!(),
+        }
+    }
+
+    /// Run a closure against the value behind a handle.
+    pub fn get<F, R>(&self, handle: u64, f: F) -> Result<R, HandleError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let slot_index = self.resolve(handle)?;
+
+        match &self.slots[slot_index as usize] {
+            Slot::Occupied { value, .. } => Ok(f(value)),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        }
+    }
+
+    /// Run a closure against the value behind a handle, allowing it to be
+    /// mutated.
+    pub fn get_mut<F, R>(&mut self, handle: u64, f: F) -> Result<R, HandleError>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let slot_index = self.resolve(handle)?;
+
+        match &mut self.slots[slot_index as usize] {
+            Slot::Occupied { value, .. } => Ok(f(value)),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        }
+    }
+
+    /// Decode a handle and make sure it actually refers to a live value in
+    /// this map, returning the slot index if so.
+    fn resolve(&self, handle: u64) -> Result<u32, HandleError> {
+        let decoded = decode(handle)?;
+
+        if decoded.map_id != self.map_id {
+            return Err(HandleError::WrongMap);
+        }
+
+        match self.slots.get(decoded.index as usize) {
+            Some(Slot::Occupied { generation, .. }) if *generation == decoded.generation => {
+                Ok(decoded.index)
+            }
+            Some(Slot::Occupied { .. }) | Some(Slot::Free { .. }) => Err(HandleError::UseAfterFree),
+            None => Err(HandleError::IndexOutOfBounds),
+        }
+    }
+}
+
+impl<T> Default for HandleMap<T> {
+    fn default() -> HandleMap<T> {
+        HandleMap::new()
+    }
+}
+
+/// A thread-safe version of [`HandleMap`] where every slot is guarded by its
+/// own lock, so looking up one handle doesn't block callers using a
+/// different one.
+pub struct ConcurrentHandleMap<T> {
+    map_id: u16,
+    type_tag: u8,
+    slots: RwLock<Vec<Mutex<Slot<T>>>>,
+    free_list_head: Mutex<Option<u32>>,
+}
+
+impl<T> ConcurrentHandleMap<T> {
+    /// Create a new, empty `ConcurrentHandleMap`.
+    pub fn new() -> ConcurrentHandleMap<T> {
+        ConcurrentHandleMap::with_type_tag(0)
+    }
+
+    /// Create a new, empty `ConcurrentHandleMap`, tagging every handle it
+    /// issues with `type_tag`.
+    pub fn with_type_tag(type_tag: u8) -> ConcurrentHandleMap<T> {
+        ConcurrentHandleMap {
+            map_id: next_map_id(),
+            type_tag,
+            slots: RwLock::new(Vec::new()),
+            free_list_head: Mutex::new(None),
+        }
+    }
+
+    /// Insert a value into the map, returning the handle it can later be
+    /// retrieved with.
+    pub fn insert(&self, value: T) -> u64 {
+        let mut free_list_head = self.free_list_head.lock().unwrap();
+
+        if let Some(index) = free_list_head.take() {
+            let slots = self.slots.read().unwrap();
+            let mut slot = slots[index as usize].lock().unwrap();
+
+            let generation = match &*slot {
+                Slot::Free { generation, next_free } => {
+                    *free_list_head = *next_free;
+                    *generation
+                }
+                Slot::Occupied { .. } => unreachable!("free list pointed at an occupied slot"),
+            };
+
+            *slot = Slot::Occupied { generation, value };
+            return encode(index, generation, self.map_id, self.type_tag);
+        }
+
+        drop(free_list_head);
+
+        let mut slots = self.slots.write().unwrap();
+        let index = slots.len() as u32;
+        slots.push(Mutex::new(Slot::Occupied { generation: 0, value }));
+
+        encode(index, 0, self.map_id, self.type_tag)
+    }
+
+    /// Remove the value behind a handle, returning it to the caller.
+    pub fn remove(&self, handle: u64) -> Result<T, HandleError> {
+        let decoded = self.resolve(handle)?;
+        let slots = self.slots.read().unwrap();
+        let mut slot = slots[decoded as usize].lock().unwrap();
+
+        let generation = match &*slot {
+            Slot::Occupied { generation, .. } => next_generation(*generation),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        };
+
+        let mut free_list_head = self.free_list_head.lock().unwrap();
+        let old = std::mem::replace(
+            &mut *slot,
+            Slot::Free {
+                generation,
+                next_free: *free_list_head,
+            },
+        );
+        *free_list_head = Some(decoded);
+
+        match old {
+            Slot::Occupied { value, .. } => Ok(value),
+            Slot::Free { .. } => unreachable!(),
+        }
+    }
+
+    /// Run a closure against the value behind a handle.
+    pub fn get<F, R>(&self, handle: u64, f: F) -> Result<R, HandleError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let decoded = self.resolve(handle)?;
+        let slots = self.slots.read().unwrap();
+        let slot = slots[decoded as usize].lock().unwrap();
+
+        match &*slot {
+            Slot::Occupied { value, .. } => Ok(f(value)),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        }
+    }
+
+    /// Run a closure against the value behind a handle, allowing it to be
+    /// mutated.
+    pub fn get_mut<F, R>(&self, handle: u64, f: F) -> Result<R, HandleError>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let decoded = self.resolve(handle)?;
+        let slots = self.slots.read().unwrap();
+        let mut slot = slots[decoded as usize].lock().unwrap();
+
+        match &mut *slot {
+            Slot::Occupied { value, .. } => Ok(f(value)),
+            Slot::Free { .. } => unreachable!("resolve() already checked this slot is occupied"),
+        }
+    }
+
+    fn resolve(&self, handle: u64) -> Result<u32, HandleError> {
+        let decoded = decode(handle)?;
+
+        if decoded.map_id != self.map_id {
+            return Err(HandleError::WrongMap);
+        }
+
+        let slots = self.slots.read().unwrap();
+        match slots.get(decoded.index as usize) {
+            Some(slot) => {
+                let slot = slot.lock().unwrap();
+                match &*slot {
+                    Slot::Occupied { generation, .. } if *generation == decoded.generation => {
+                        Ok(decoded.index)
+                    }
+                    Slot::Occupied { .. } | Slot::Free { .. } => Err(HandleError::UseAfterFree),
+                }
+            }
+            None => Err(HandleError::IndexOutOfBounds),
+        }
+    }
+}
+
+impl<T> Default for ConcurrentHandleMap<T> {
+    fn default() -> ConcurrentHandleMap<T> {
+        ConcurrentHandleMap::new()
+    }
+}
+
+/// Look a handle up in a map, reporting any failure through
+/// [`update_last_error()`] instead of returning it directly. Handy when
+/// writing an `extern "C"` function where the error needs to end up in the
+/// usual last-error channel.
+pub fn get_or_update_last_error<T, F, R>(map: &HandleMap<T>, handle: u64, f: F) -> Option<R>
+where
+    F: FnOnce(&T) -> R,
+{
+    match map.get(handle, f) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            update_last_error(e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let handle = encode(42, 7, 1234, 5);
+        let decoded = decode(handle).unwrap();
+
+        assert_eq!(decoded.index, 42);
+        assert_eq!(decoded.generation, 7);
+        assert_eq!(decoded.map_id, 1234);
+        assert_eq!(decoded.type_tag, 5);
+    }
+
+    #[test]
+    fn corrupted_handle_is_rejected() {
+        let handle = encode(42, 7, 1234, 5);
+
+        // Flipping any single bit flips the parity too, so the corruption
+        // should always be caught.
+        let corrupted = handle ^ (1 << 3);
+
+        assert_eq!(decode(corrupted), Err(HandleError::CorruptHandle));
+    }
+
+    #[test]
+    fn insert_then_get() {
+        let mut map = HandleMap::new();
+        let handle = map.insert(String::from("hello"));
+
+        let value = map.get(handle, |s| s.clone()).unwrap();
+
+        assert_eq!(value, "hello");
+    }
+
+    #[test]
+    fn removed_handle_is_use_after_free() {
+        let mut map = HandleMap::new();
+        let handle = map.insert(String::from("hello"));
+
+        map.remove(handle).unwrap();
+
+        assert_eq!(
+            map.get(handle, |_| ()),
+            Err(HandleError::UseAfterFree)
+        );
+    }
+
+    #[test]
+    fn handle_from_a_different_map_is_rejected() {
+        let mut first = HandleMap::new();
+        let second: HandleMap<String> = HandleMap::new();
+
+        let handle = first.insert(String::from("hello"));
+
+        assert_eq!(second.get(handle, |_| ()), Err(HandleError::WrongMap));
+    }
+
+    #[test]
+    fn out_of_bounds_index_is_rejected() {
+        let map: HandleMap<String> = HandleMap::new();
+        // An empty map still has a real `map_id`, so build a handle that at
+        // least belongs to this map but points past its (empty) slots.
+        let bogus = encode(0, 0, map.map_id, map.type_tag);
+
+        assert_eq!(map.get(bogus, |_| ()), Err(HandleError::IndexOutOfBounds));
+    }
+
+    #[test]
+    fn generation_wraps_instead_of_overflowing_its_encoded_width() {
+        // Regression test: the stored generation used to be bumped with the
+        // full `u16` range while only `GENERATION_BITS` of it are ever
+        // encoded into a handle, so after enough re-use a freshly issued,
+        // genuinely valid handle would get rejected as a use-after-free.
+        let mut map = HandleMap::new();
+        let mut handle = map.insert(0);
+
+        for i in 1..=(u32::from(MAX_GENERATION) + 10) {
+            map.remove(handle).unwrap();
+            handle = map.insert(i);
+
+            assert_eq!(map.get(handle, |v| *v).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn concurrent_map_insert_get_remove() {
+        let map = ConcurrentHandleMap::new();
+        let handle = map.insert(String::from("hello"));
+
+        assert_eq!(map.get(handle, |s| s.clone()).unwrap(), "hello");
+
+        let value = map.remove(handle).unwrap();
+        assert_eq!(value, "hello");
+        assert_eq!(map.get(handle, |_| ()), Err(HandleError::UseAfterFree));
+    }
+}
@@ -21,7 +21,12 @@
 
 extern crate libc;
 
+pub mod backtrace;
+pub mod handle_map;
+pub mod strings;
+
 use std::error::Error;
+use std::fmt::{self, Display};
 use std::cell::RefCell;
 use std::ptr;
 use std::slice;
@@ -32,22 +37,110 @@ use libc::{c_char, c_int};
 
 thread_local!(
     static LAST_ERROR: RefCell<Option<Box<Error>>> = RefCell::new(None);
+    static LAST_ERROR_CODE: RefCell<ErrorCode> = RefCell::new(ErrorCode::NO_ERROR);
 );
 
-/// Set the thread-local `LAST_ERROR` variable.
-pub fn update_last_error<E: Into<Box<Error>> + 'static>(e: E) {
+/// A stable, numeric category for an error, so a C caller can `switch` on
+/// the kind of failure instead of string-matching `error_message()`.
+///
+/// By convention `0` means "no error", negative values are reserved for
+/// panics, and positive values are free for domain-specific error kinds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ErrorCode(pub i32);
+
+impl ErrorCode {
+    /// Nothing has gone wrong.
+    pub const NO_ERROR: ErrorCode = ErrorCode(0);
+    /// The last operation unwound via a panic rather than returning `Err`.
+    pub const PANIC: ErrorCode = ErrorCode(-1);
+    /// An error occurred but its type didn't specify anything more precise.
+    pub const UNKNOWN: ErrorCode = ErrorCode(-2);
+}
+
+/// Implemented by error types which know which [`ErrorCode`] they should be
+/// reported as.
+///
+/// Blanket-implemented for every `E: Error`, always reporting
+/// [`ErrorCode::UNKNOWN`], so any existing error type - your own, a
+/// third-party error, whatever - works with [`update_last_error()`] and
+/// `#[catch_panic]` without writing an impl of your own. There's
+/// deliberately no way to override this yourself: a type that needs a more
+/// specific code (like the panic-catching machinery's [`PanicError`], which
+/// always reports [`ErrorCode::PANIC`]) goes through its own dedicated path
+/// instead of this trait.
+pub trait HasErrorCode: Error {
+    /// Which [`ErrorCode`] should be reported to the C caller for this
+    /// error.
+    fn error_code(&self) -> ErrorCode {
+        ErrorCode::UNKNOWN
+    }
+}
+
+impl<E: Error> HasErrorCode for E {}
+
+/// Set the thread-local `LAST_ERROR` variable, along with the [`ErrorCode`]
+/// `e` reports via [`HasErrorCode`].
+pub fn update_last_error<E: HasErrorCode + Into<Box<Error>> + 'static>(e: E) {
+    let code = e.error_code();
     let boxed = e.into();
 
     LAST_ERROR.with(|last| {
         *last.borrow_mut() = Some(boxed);
     });
+    LAST_ERROR_CODE.with(|last| {
+        *last.borrow_mut() = code;
+    });
 }
 
 /// Get the last error, clearing the variable in the process.
+///
+/// Note that this leaves [`last_error_code()`] untouched; use
+/// [`take_last_error()`] if you want both drained together.
 pub fn get_last_error() -> Option<Box<Error>> {
     LAST_ERROR.with(|last| last.borrow_mut().take())
 }
 
+/// Get the [`ErrorCode`] of the last error as a raw `c_int`, without
+/// consuming it.
+#[no_mangle]
+pub extern "C" fn last_error_code() -> c_int {
+    LAST_ERROR_CODE.with(|code| code.borrow().0)
+}
+
+/// An error, repr-C'd for handing across the FFI boundary in one go instead
+/// of the "guess the buffer size, call twice" dance `error_message()`
+/// requires.
+///
+/// `message` is heap-allocated and owned by the caller once they receive
+/// it; free it with [`strings::destroy_c_string()`] once done. When there
+/// was no error, `code` is [`ErrorCode::NO_ERROR`] and `message` is `null`;
+/// `message` is also `null` if the error's message contains a nul byte.
+#[repr(C)]
+pub struct ExternError {
+    pub code: i32,
+    pub message: *mut c_char,
+}
+
+/// Atomically drain the thread-local error (and its code) into an
+/// [`ExternError`] the caller owns.
+#[no_mangle]
+pub extern "C" fn take_last_error() -> ExternError {
+    let code = LAST_ERROR_CODE.with(|c| {
+        std::mem::replace(&mut *c.borrow_mut(), ErrorCode::NO_ERROR)
+    });
+
+    match get_last_error() {
+        Some(e) => ExternError {
+            code: code.0,
+            message: strings::rust_string_to_c(format!("{}", e)),
+        },
+        None => ExternError {
+            code: ErrorCode::NO_ERROR.0,
+            message: ptr::null_mut(),
+        },
+    }
+}
+
 
 /// Write the latest error message to a buffer.
 ///
@@ -62,8 +155,6 @@ pub unsafe extern "C" fn error_message(buffer: *mut c_char, length: c_int) -> c_
         return -1;
     }
 
-    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
-
     // Take the last error, if there isn't one then there's no error message to
     // display.
     let err = match get_last_error() {
@@ -72,23 +163,69 @@ pub unsafe extern "C" fn error_message(buffer: *mut c_char, length: c_int) -> c_
     };
 
     let error_message = format!("{}", err);
-    let bytes_required = error_message.len() + 1;
 
-    if buffer.len() < bytes_required {
-        // We don't have enough room. Make sure to return the error so it
-        // isn't accidentally consumed
-        update_last_error(err);
+    match write_to_buffer(&error_message, buffer, length) {
+        Some(bytes_written) => bytes_written,
+        None => {
+            // We don't have enough room. Put the error back (without
+            // touching its already-stored code) so it isn't accidentally
+            // consumed.
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
+            -1
+        }
+    }
+}
+
+/// Write the backtrace captured for the last panic to a buffer, using the
+/// same contract as [`error_message()`].
+///
+/// This only has something to write if [`backtrace::enable_backtraces()`]
+/// was called before the panic happened; otherwise (the default) it behaves
+/// as if there was no backtrace at all.
+#[no_mangle]
+pub unsafe extern "C" fn error_backtrace(buffer: *mut c_char, length: c_int) -> c_int {
+    if buffer.is_null() {
         return -1;
     }
 
-    let data = error_message.as_bytes();
+    let captured = match backtrace::take_last_backtrace() {
+        Some(b) => b,
+        None => return 0,
+    };
+
+    let formatted = format!("{}", captured);
+
+    match write_to_buffer(&formatted, buffer, length) {
+        Some(bytes_written) => bytes_written,
+        None => {
+            // We don't have enough room. Put the backtrace back so a caller
+            // that retries with a bigger buffer can still get at it.
+            backtrace::restore_last_backtrace(captured);
+            -1
+        }
+    }
+}
+
+/// Copy `message` into `buffer`, zeroing out anything left over, and report
+/// how many bytes were written. Returns `None` if `buffer` isn't big enough
+/// to hold `message` plus its trailing nul.
+unsafe fn write_to_buffer(message: &str, buffer: *mut c_char, length: c_int) -> Option<c_int> {
+    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+
+    let bytes_required = message.len() + 1;
+
+    if buffer.len() < bytes_required {
+        return None;
+    }
+
+    let data = message.as_bytes();
     ptr::copy_nonoverlapping(data.as_ptr(), buffer.as_mut_ptr(), data.len());
 
     // zero out the rest of the buffer just in case
     let rest = &mut buffer[data.len()..];
     ptr::write_bytes(rest.as_mut_ptr(), 0, rest.len());
 
-    data.len() as c_int
+    Some(data.len() as c_int)
 }
 
 /// Execute some closure, catching any panics and converting them into errors
@@ -160,7 +297,7 @@ pub unsafe extern "C" fn error_message(buffer: *mut c_char, length: c_int) -> c_
 ///   fn from(other: Box<Any + Send + 'static>) -> Error {
 ///     if let Some(owned) = other.downcast_ref::<String>() {
 ///       Error::Message(owned.clone())
-///     } else if let Some(owned) = other.downcast_ref::<String>() {
+///     } else if let Some(owned) = other.downcast_ref::<&str>() {
 ///       Error::Message(owned.to_string())
 ///     } else {
 ///       Error::Unknown
@@ -169,6 +306,14 @@ pub unsafe extern "C" fn error_message(buffer: *mut c_char, length: c_int) -> c_
 /// }
 /// ```
 ///
+/// # Backtraces
+///
+/// If [`backtrace::install_panic_hook()`] has been called and
+/// [`backtrace::enable_backtraces()`] is turned on, a panic caught here will
+/// already have had its backtrace stashed away by the time this function
+/// returns; fetch it with [`error_backtrace()`] before triggering another
+/// panic.
+///
 /// [cu]: https://doc.rust-lang.org/std/panic/fn.catch_unwind.html
 /// [error-chain]: https://crates.io/crates/error-chain
 pub fn catch_panic<T, E, F>(func: F) -> Result<T, E>
@@ -180,3 +325,73 @@ where
         .map_err(Into::into)
         .and_then(|t| t)
 }
+
+/// An error type representing a caught panic, used by the `#[catch_panic]`
+/// attribute (see the `ffi_helpers_derive` crate) so a panic payload can be
+/// stored as the last error via [`update_last_panic_error()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicError {
+    message: String,
+    backtrace: Option<String>,
+}
+
+impl PanicError {
+    /// The backtrace captured for this panic, if [`backtrace::enable_backtraces()`]
+    /// was turned on before it happened.
+    ///
+    /// This is the same information [`error_backtrace()`] reads off the
+    /// thread-local, attached directly to the error instead of requiring a
+    /// second call that has to happen before the next panic overwrites it.
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref()
+    }
+}
+
+impl Display for PanicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a panic occurred: {}", self.message)
+    }
+}
+
+impl Error for PanicError {}
+
+/// Store a caught panic as the last error, always reporting
+/// [`ErrorCode::PANIC`].
+///
+/// `PanicError` doesn't go through [`HasErrorCode`]/[`update_last_error()`]
+/// like other errors - that trait's blanket impl can only ever report
+/// [`ErrorCode::UNKNOWN`], so the panic-catching machinery sets the code
+/// directly instead.
+pub fn update_last_panic_error(e: PanicError) {
+    let boxed: Box<Error> = Box::new(e);
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(boxed));
+    LAST_ERROR_CODE.with(|last| *last.borrow_mut() = ErrorCode::PANIC);
+}
+
+/// Try to recover a human-readable message from a panic payload, the same
+/// way the default panic handler does.
+///
+/// If a backtrace was captured for this panic (see the [`backtrace`] module),
+/// it's attached to the resulting [`PanicError`] via [`PanicError::backtrace()`]
+/// rather than left to be fetched separately through [`error_backtrace()`].
+pub fn panic_to_error(payload: Box<Any + Send + 'static>) -> PanicError {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<Any>")
+    };
+
+    PanicError {
+        message,
+        backtrace: backtrace::peek_last_backtrace(),
+    }
+}
+
+/// The `#[catch_panic]` attribute from the `ffi_helpers_derive` crate,
+/// re-exported so binding authors only need to depend on `ffi_helpers`.
+/// Enabled with the `derive` feature.
+#[cfg(feature = "derive")]
+pub use ffi_helpers_derive::catch_panic;
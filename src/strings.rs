@@ -0,0 +1,188 @@
+//! Safe helpers for marshalling strings across the FFI boundary.
+//!
+//! Most FFI memory bugs live in string handling: forgetting to check for
+//! `null`, assuming a `*const c_char` is valid UTF-8, or freeing a string
+//! with the wrong allocator. [`FfiStr`] covers the "C gives us a string"
+//! direction, while [`rust_string_to_c()`] and [`destroy_c_string()`] cover
+//! "we give C a string" and make sure it's freed through the allocator that
+//! created it.
+
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+use std::ptr;
+
+use libc::c_char;
+
+use super::update_last_error;
+
+/// A borrowed, possibly-`null`, `*const c_char` from C.
+///
+/// This doesn't own the string it points to, so it's only valid for as long
+/// as the pointer C gave you is (typically the duration of the call it was
+/// passed into).
+#[derive(Debug, Copy, Clone)]
+pub struct FfiStr<'a> {
+    raw: *const c_char,
+    _marker: PhantomData<&'a c_char>,
+}
+
+impl<'a> FfiStr<'a> {
+    /// Wrap a raw `*const c_char`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must either be `null` or point to a valid, nul-terminated
+    /// string that lives at least as long as `'a`.
+    pub unsafe fn from_raw(raw: *const c_char) -> FfiStr<'a> {
+        FfiStr {
+            raw,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Validate and borrow the string, treating a `null` pointer as a
+    /// missing argument rather than an error.
+    pub fn as_opt_str(&self) -> Option<&'a str> {
+        if self.raw.is_null() {
+            return None;
+        }
+
+        self.validate()
+    }
+
+    /// Validate and borrow the string, reporting a `null` pointer through
+    /// [`update_last_error()`] as [`FfiStrError::NullPointer`].
+    pub fn as_str(&self) -> Option<&'a str> {
+        if self.raw.is_null() {
+            update_last_error(FfiStrError::NullPointer);
+            return None;
+        }
+
+        self.validate()
+    }
+
+    fn validate(&self) -> Option<&'a str> {
+        let c_str = unsafe { CStr::from_ptr(self.raw) };
+
+        match c_str.to_str() {
+            Ok(s) => Some(s),
+            Err(e) => {
+                update_last_error(FfiStrError::InvalidUtf8(e));
+                None
+            }
+        }
+    }
+}
+
+/// The ways borrowing an [`FfiStr`] can fail.
+#[derive(Debug)]
+pub enum FfiStrError {
+    /// [`FfiStr::as_str()`] was called on a `null` pointer.
+    NullPointer,
+    /// The string wasn't valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+}
+
+impl Display for FfiStrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FfiStrError::NullPointer => write!(f, "expected a string, found a null pointer"),
+            FfiStrError::InvalidUtf8(e) => write!(f, "invalid UTF-8: {}", e),
+        }
+    }
+}
+
+impl Error for FfiStrError {}
+
+/// Hand a `String` over to C as a `null`-terminated, heap-allocated
+/// `*mut c_char`. Free it with [`destroy_c_string()`] once you're done with
+/// it, not with C's `free()`.
+///
+/// Any interior nul bytes make the conversion impossible, in which case
+/// `null` is returned rather than panicking or silently truncating the
+/// string.
+pub fn rust_string_to_c(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Free a string previously returned by [`rust_string_to_c()`] (or
+/// [`take_last_error()`](crate::take_last_error)). Safe to call with a
+/// `null` pointer.
+///
+/// # Safety
+///
+/// `s` must either be `null` or a pointer this crate handed back to C;
+/// passing anything else (or calling this twice on the same pointer) is
+/// undefined behaviour.
+#[no_mangle]
+pub unsafe extern "C" fn destroy_c_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_pointer_is_not_an_error_for_as_opt_str() {
+        let s = unsafe { FfiStr::from_raw(ptr::null()) };
+
+        assert_eq!(s.as_opt_str(), None);
+        assert!(crate::get_last_error().is_none());
+    }
+
+    #[test]
+    fn null_pointer_is_an_error_for_as_str() {
+        let s = unsafe { FfiStr::from_raw(ptr::null()) };
+
+        assert_eq!(s.as_str(), None);
+        assert!(crate::get_last_error().is_some());
+    }
+
+    #[test]
+    fn valid_utf8_round_trips() {
+        let c_string = CString::new("Hello, World!").unwrap();
+        let s = unsafe { FfiStr::from_raw(c_string.as_ptr()) };
+
+        assert_eq!(s.as_str(), Some("Hello, World!"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_instead_of_panicking() {
+        let invalid = CString::new(vec![0xff, 0xfe]).unwrap();
+        let s = unsafe { FfiStr::from_raw(invalid.as_ptr()) };
+
+        assert_eq!(s.as_str(), None);
+        assert!(crate::get_last_error().is_some());
+    }
+
+    #[test]
+    fn rust_string_to_c_and_destroy_round_trip() {
+        let raw = rust_string_to_c(String::from("round trip"));
+        assert!(!raw.is_null());
+
+        let recovered = unsafe { CStr::from_ptr(raw) };
+        assert_eq!(recovered.to_str(), Ok("round trip"));
+
+        unsafe { destroy_c_string(raw) };
+    }
+
+    #[test]
+    fn destroy_c_string_accepts_null() {
+        unsafe { destroy_c_string(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn interior_nul_bytes_cant_be_converted() {
+        let with_interior_nul = String::from("uh\0oh");
+
+        assert!(rust_string_to_c(with_interior_nul).is_null());
+    }
+}